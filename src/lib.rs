@@ -1,5 +1,11 @@
 #![cfg(any(unix, windows))]
 
+use std::alloc::{GlobalAlloc, Layout};
+use std::{cmp, fmt, mem};
+
+mod secure;
+pub use secure::{secure_alloc, secure_free};
+
 /// Allocates `size` Bytes aligned to `align` Bytes. Returns a null pointer on allocation failure.
 ///
 /// The returned pointer must be deallocated by using `aligned_free`.
@@ -26,13 +32,160 @@ pub unsafe fn aligned_free(ptr: *mut ()) {
     imp::aligned_free(ptr)
 }
 
+/// Resizes an aligned allocation previously obtained from `aligned_alloc`.
+///
+/// Always performs an allocate-copy-free: a new `new_size`-Byte block aligned to `align` is
+/// allocated, `min(old_size, new_size)` Bytes are copied over from `ptr`, and the old block is
+/// freed. Returns a null pointer on allocation failure, in which case `ptr` is left untouched and
+/// still valid.
+///
+/// On Windows this is unavoidable, since a `VirtualAlloc` reservation cannot be resized in place;
+/// on Unix the same allocate-copy-free strategy is used because the resulting pointer must stay
+/// aligned to `align`, which plain `realloc` does not guarantee.
+///
+/// # Safety
+///
+/// `ptr` must have been allocated by `aligned_alloc` with the same `align`, and `old_size` must
+/// match the size it was allocated with. Per `GlobalAlloc::realloc`'s semantics, accessing `ptr`
+/// after a successful call is undefined behavior, even if the returned pointer happens to equal
+/// it.
+#[inline]
+pub unsafe fn aligned_realloc(ptr: *mut (), old_size: usize, new_size: usize, align: usize) -> *mut () {
+    imp::aligned_realloc(ptr, old_size, new_size, align)
+}
+
+/// Allocates a zeroed `size`-Byte block aligned to `align` Bytes, analogous to `calloc`.
+///
+/// Returns a null pointer on allocation failure. The returned pointer must be deallocated with
+/// `aligned_free`, exactly like a pointer from `aligned_alloc`.
+#[inline]
+pub fn aligned_alloc_zeroed(size: usize, align: usize) -> *mut () {
+    imp::aligned_alloc_zeroed(size, align)
+}
+
+/// An error returned by `try_aligned_alloc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignError {
+    /// `align` was not a power of two, or was smaller than `size_of::<usize>()`.
+    InvalidAlign,
+    /// The allocator could not satisfy the request.
+    OutOfMemory,
+}
+
+impl fmt::Display for AlignError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AlignError::InvalidAlign => {
+                write!(f, "align must be a power of two and at least size_of::<usize>()")
+            }
+            AlignError::OutOfMemory => write!(f, "allocator is out of memory"),
+        }
+    }
+}
+
+impl ::std::error::Error for AlignError {}
+
+/// A fallible, C11-`aligned_alloc`-compatible allocation function.
+///
+/// Unlike `aligned_alloc`, this never panics: requests with an invalid `align` (not a power of
+/// two, or smaller than `size_of::<usize>()`) are rejected with `Err(AlignError::InvalidAlign)`
+/// instead of succeeding silently or aborting the process, and allocation failure is reported as
+/// `Err(AlignError::OutOfMemory)` rather than conflated with a null pointer.
+///
+/// On macOS and Illumos, `posix_memalign` additionally requires `align` to be at least
+/// `size_of::<*const ()>()`; smaller-but-otherwise-legal alignments are normalized up to that
+/// floor internally so behavior is uniform across Unix platforms.
+///
+/// # Parameters
+///
+/// * `size`: The size of the allocation in bytes.
+/// * `align`: The alignment of the allocation. Must be a power of two and at least
+///   `size_of::<usize>()`.
+pub fn try_aligned_alloc(size: usize, align: usize) -> Result<*mut (), AlignError> {
+    if !align.is_power_of_two() || align < mem::size_of::<usize>() {
+        return Err(AlignError::InvalidAlign);
+    }
+
+    imp::try_aligned_alloc(size, align)
+}
+
+/// Returns how many bytes an `aligned_alloc(size, align)` allocation actually backs.
+///
+/// Allocators commonly hand back more than was asked for (rounding up to a size class or a page),
+/// and callers that track their own length can use that slack instead of reallocating when they
+/// need to grow slightly. This never returns less than `size`.
+///
+/// On Windows, the `VirtualAlloc` commit is page-rounded, so this is `size` rounded up to the
+/// page size. On Unix, it's wired to `malloc_usable_size` where available, falling back to
+/// returning `size` unchanged on platforms that don't expose it.
+#[inline]
+pub fn aligned_usable_size(size: usize, align: usize) -> usize {
+    imp::aligned_usable_size(size, align)
+}
+
+/// The alignment the system allocator (`malloc`/`HeapAlloc`) already guarantees for every
+/// allocation, regardless of the requested alignment.
+///
+/// Matches the standard library's own system allocator: 8 Bytes on 32-bit platforms, 16 Bytes
+/// on 64-bit ones.
+#[cfg(target_pointer_width = "32")]
+const MIN_ALIGN: usize = 8;
+#[cfg(target_pointer_width = "64")]
+const MIN_ALIGN: usize = 16;
+
+/// A `GlobalAlloc` implementation backed by this crate, suitable for use as a
+/// `#[global_allocator]`.
+///
+/// ```no_run
+/// use aligned_alloc::AlignedSystem;
+///
+/// #[global_allocator]
+/// static ALLOC: AlignedSystem = AlignedSystem;
+/// ```
+///
+/// Since `aligned_alloc`/`aligned_free` are documented as slow compared to a plain `malloc`/
+/// `free` round-trip, `alloc` and `dealloc` take a fast path straight to the system allocator
+/// whenever the requested alignment is already satisfied by it (`layout.align() <= MIN_ALIGN`)
+/// and doesn't exceed the requested size, and only fall back to `posix_memalign`/`VirtualAlloc`
+/// for genuinely over-aligned requests.
+pub struct AlignedSystem;
+
+unsafe impl GlobalAlloc for AlignedSystem {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
+            imp::malloc(layout.size()) as *mut u8
+        } else {
+            // `Layout` only guarantees `align` is a power of two, not that it meets
+            // `aligned_alloc`'s `>= size_of::<usize>()` floor (e.g. `align(2)` is perfectly
+            // valid). Over-aligning is always safe, so bump it up rather than risk the panicking
+            // EINVAL path for an otherwise ordinary allocation.
+            let align = cmp::max(layout.align(), mem::size_of::<usize>());
+            aligned_alloc(layout.size(), align) as *mut u8
+        }
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // `dealloc` must route to whichever allocator `alloc` would have used for this layout:
+        // on Unix both paths end in `free`, but on Windows the fast path releases through
+        // `HeapFree` and the aligned path through `VirtualFree`, and each only accepts pointers
+        // from its own allocation call.
+        if layout.align() <= MIN_ALIGN && layout.align() <= layout.size() {
+            imp::free(ptr as *mut ())
+        } else {
+            aligned_free(ptr as *mut ())
+        }
+    }
+}
+
 #[cfg(unix)]
 mod imp {
     extern crate libc;
 
-    use self::libc::{c_void, c_int, size_t, EINVAL, ENOMEM, free};
+    use self::libc::{c_void, c_int, size_t, EINVAL, ENOMEM, calloc};
 
-    use std::{mem, ptr};
+    use std::{cmp, mem, ptr};
 
     extern "C" {
         fn posix_memalign(memptr: *mut *mut c_void, alignment: size_t, size: size_t) -> c_int;
@@ -60,7 +213,79 @@ mod imp {
 
     #[inline]
     pub unsafe fn aligned_free(ptr: *mut ()) {
-        free(ptr as *mut c_void)
+        self::libc::free(ptr as *mut c_void)
+    }
+
+    pub unsafe fn aligned_realloc(ptr: *mut (), old_size: usize, new_size: usize, align: usize)
+        -> *mut () {
+        let new_ptr = aligned_alloc(new_size, align);
+        if new_ptr.is_null() {
+            return ptr::null_mut();
+        }
+
+        ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, cmp::min(old_size, new_size));
+        free(ptr);
+        new_ptr
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    extern "C" {
+        fn malloc_usable_size(ptr: *mut c_void) -> size_t;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn aligned_usable_size(size: usize, align: usize) -> usize {
+        let ptr = aligned_alloc(size, align);
+        if ptr.is_null() {
+            return size;
+        }
+
+        let usable = unsafe { malloc_usable_size(ptr as *mut c_void) as usize };
+        unsafe { self::libc::free(ptr as *mut c_void) };
+        usable
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn aligned_usable_size(size: usize, _align: usize) -> usize {
+        size
+    }
+
+    pub fn try_aligned_alloc(size: usize, align: usize) -> Result<*mut (), super::AlignError> {
+        // macOS/Illumos require `posix_memalign`'s alignment to be at least `sizeof(void*)`;
+        // normalize smaller-but-legal alignments up to that floor so behavior is uniform.
+        let align = cmp::max(align, mem::size_of::<*const ()>());
+
+        let mut memptr: *mut c_void = ptr::null_mut();
+        let result = unsafe { posix_memalign(&mut memptr, align as size_t, size as size_t) };
+        match result {
+            0 => Ok(memptr as *mut ()),
+            ENOMEM => Err(super::AlignError::OutOfMemory),
+            _ => unreachable!("posix_memalign returned unexpected error {}", result),
+        }
+    }
+
+    pub fn aligned_alloc_zeroed(size: usize, align: usize) -> *mut () {
+        if align <= super::MIN_ALIGN && align <= size {
+            // Lets the OS hand back demand-zero pages straight from `calloc` without an explicit
+            // memset.
+            return unsafe { calloc(1, size as size_t) as *mut () };
+        }
+
+        let ptr = aligned_alloc(size, align);
+        if !ptr.is_null() {
+            unsafe { ptr::write_bytes(ptr as *mut u8, 0, size); }
+        }
+        ptr
+    }
+
+    #[inline]
+    pub unsafe fn malloc(size: usize) -> *mut () {
+        self::libc::malloc(size as size_t) as *mut ()
+    }
+
+    #[inline]
+    pub unsafe fn free(ptr: *mut ()) {
+        self::libc::free(ptr as *mut c_void)
     }
 }
 
@@ -69,10 +294,12 @@ mod imp {
     extern crate winapi;
     extern crate kernel32;
 
-    use self::kernel32::{GetLastError, GetSystemInfo, VirtualAlloc, VirtualFree};
+    use self::kernel32::{GetLastError, GetSystemInfo, VirtualAlloc, VirtualFree, GetProcessHeap,
+        HeapAlloc, HeapFree};
     use self::winapi::{MEM_COMMIT, MEM_RESERVE, MEM_RELEASE, PAGE_NOACCESS, PAGE_READWRITE, SIZE_T,
         LPVOID, DWORD, SYSTEM_INFO};
 
+    use std::cmp;
     use std::mem;
     use std::ptr;
 
@@ -127,11 +354,96 @@ mod imp {
             panic!("WINAPI error {} while releasing memory", GetLastError());
         }
     }
+
+    pub unsafe fn aligned_realloc(ptr: *mut (), old_size: usize, new_size: usize, align: usize)
+        -> *mut () {
+        // A `VirtualAlloc` reservation can't be grown or shrunk in place, so this is always a
+        // fresh allocate-copy-free.
+        let new_ptr = aligned_alloc(new_size, align);
+        if new_ptr.is_null() {
+            return ptr::null_mut();
+        }
+
+        ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, cmp::min(old_size, new_size));
+        aligned_free(ptr);
+        new_ptr
+    }
+
+    pub fn aligned_usable_size(size: usize, _align: usize) -> usize {
+        if unsafe { PAGE_SIZE } == 0 { get_page_size() }
+        let page_size = unsafe { PAGE_SIZE } as usize;
+
+        (size + page_size - 1) & !(page_size - 1)
+    }
+
+    pub fn try_aligned_alloc(size: usize, align: usize) -> Result<*mut (), super::AlignError> {
+        // This mirrors `aligned_alloc` above, but reports every WINAPI failure (including the
+        // `VirtualFree` call that releases the over-sized reservation) as
+        // `Err(AlignError::OutOfMemory)` instead of panicking, since this function exists
+        // specifically to never do that.
+        if unsafe { PAGE_SIZE } == 0 { get_page_size() }
+
+        unsafe {
+            if align <= PAGE_SIZE as usize {
+                let ptr = VirtualAlloc(ptr::null_mut(), size as SIZE_T, MEM_COMMIT | MEM_RESERVE,
+                    PAGE_READWRITE);
+                return if ptr.is_null() {
+                    Err(super::AlignError::OutOfMemory)
+                } else {
+                    Ok(ptr as *mut ())
+                };
+            }
+
+            let ptr = VirtualAlloc(ptr::null_mut(), (size + align - 1) as SIZE_T, MEM_RESERVE,
+                PAGE_NOACCESS);
+            if ptr.is_null() {
+                return Err(super::AlignError::OutOfMemory);
+            }
+
+            let aligned_ptr = (ptr as usize + align - 1) & !(align - 1);
+
+            if VirtualFree(ptr as LPVOID, 0, MEM_RELEASE) == 0 {
+                return Err(super::AlignError::OutOfMemory);
+            }
+
+            let ptr = VirtualAlloc(aligned_ptr as LPVOID, size as SIZE_T, MEM_COMMIT | MEM_RESERVE,
+                PAGE_READWRITE);
+            if ptr.is_null() {
+                Err(super::AlignError::OutOfMemory)
+            } else {
+                Ok(ptr as *mut ())
+            }
+        }
+    }
+
+    pub fn aligned_alloc_zeroed(size: usize, align: usize) -> *mut () {
+        // Freshly committed `VirtualAlloc` pages are always zero-filled, so there's nothing left
+        // to do here; just sanity-check that assumption in debug builds.
+        let ptr = aligned_alloc(size, align);
+        if cfg!(debug_assertions) && !ptr.is_null() && size > 0 {
+            unsafe { debug_assert_eq!(*(ptr as *const u8), 0); }
+        }
+        ptr
+    }
+
+    #[inline]
+    pub unsafe fn malloc(size: usize) -> *mut () {
+        HeapAlloc(GetProcessHeap(), 0, size as SIZE_T) as *mut ()
+    }
+
+    #[inline]
+    pub unsafe fn free(ptr: *mut ()) {
+        let res = HeapFree(GetProcessHeap(), 0, ptr as LPVOID);
+        if res == 0 {
+            panic!("WINAPI error {} while releasing memory", GetLastError());
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::ptr;
 
     #[test]
     fn small_low_align() {
@@ -176,4 +488,124 @@ mod tests {
     fn align_zero() {
         aligned_alloc(1, 0);
     }
+
+    #[test]
+    fn realloc_grow_preserves_contents() {
+        let ptr = aligned_alloc(8, 8);
+        assert!(!ptr.is_null());
+        unsafe {
+            ptr::write_bytes(ptr as *mut u8, 0xab, 8);
+            let new_ptr = aligned_realloc(ptr, 8, 64, 8);
+            assert!(!new_ptr.is_null());
+            assert_eq!(new_ptr as usize % 8, 0);
+            for i in 0..8 {
+                assert_eq!(*(new_ptr as *const u8).add(i), 0xab);
+            }
+            aligned_free(new_ptr);
+        }
+    }
+
+    #[test]
+    fn realloc_shrink_preserves_prefix() {
+        let ptr = aligned_alloc(64, 8);
+        assert!(!ptr.is_null());
+        unsafe {
+            ptr::write_bytes(ptr as *mut u8, 0xcd, 64);
+            let new_ptr = aligned_realloc(ptr, 64, 8, 8);
+            assert!(!new_ptr.is_null());
+            for i in 0..8 {
+                assert_eq!(*(new_ptr as *const u8).add(i), 0xcd);
+            }
+            aligned_free(new_ptr);
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed_is_actually_zero_fast_path() {
+        // `align <= MIN_ALIGN && align <= size` routes through the calloc/`VirtualAlloc` fast
+        // path rather than the explicit `ptr::write_bytes` fallback.
+        let ptr = aligned_alloc_zeroed(64, 8);
+        assert!(!ptr.is_null());
+        unsafe {
+            for i in 0..64 {
+                assert_eq!(*(ptr as *const u8).add(i), 0);
+            }
+            aligned_free(ptr);
+        }
+    }
+
+    #[test]
+    fn alloc_zeroed_is_actually_zero_slow_path() {
+        // An over-aligned request forces the posix_memalign/`VirtualAlloc` + explicit zero-fill
+        // path.
+        let ptr = aligned_alloc_zeroed(8, 1024 * 1024);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % (1024 * 1024), 0);
+        unsafe {
+            for i in 0..8 {
+                assert_eq!(*(ptr as *const u8).add(i), 0);
+            }
+            aligned_free(ptr);
+        }
+    }
+
+    #[test]
+    fn try_aligned_alloc_rejects_invalid_align() {
+        assert_eq!(try_aligned_alloc(1, 0), Err(AlignError::InvalidAlign));
+        assert_eq!(try_aligned_alloc(1, 3), Err(AlignError::InvalidAlign));
+        assert_eq!(try_aligned_alloc(1, 27), Err(AlignError::InvalidAlign));
+    }
+
+    #[test]
+    fn try_aligned_alloc_succeeds_for_valid_align() {
+        let ptr = try_aligned_alloc(8, 1024 * 1024).expect("allocation should succeed");
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % (1024 * 1024), 0);
+        unsafe { aligned_free(ptr) }
+    }
+
+    #[test]
+    fn usable_size_at_least_requested() {
+        assert!(aligned_usable_size(1, 128) >= 1);
+        assert!(aligned_usable_size(1024 * 1024, 1024 * 1024) >= 1024 * 1024);
+    }
+
+    #[test]
+    fn aligned_system_fast_path() {
+        let alloc = AlignedSystem;
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 8, 0);
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn aligned_system_slow_path() {
+        let alloc = AlignedSystem;
+        let layout = Layout::from_size_align(8, 1024 * 1024).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % (1024 * 1024), 0);
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn aligned_system_align_smaller_than_usize_does_not_panic() {
+        // Regression test: `align(2) > size(1)` is a legal `Layout` (there's no requirement that
+        // `size >= align`) that used to hit the panicking EINVAL path on Unix, because `align`
+        // was smaller than `size_of::<usize>()`.
+        let alloc = AlignedSystem;
+        let layout = Layout::from_size_align(1, 2).unwrap();
+        unsafe {
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 2, 0);
+            alloc.dealloc(ptr, layout);
+        }
+    }
 }
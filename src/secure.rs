@@ -0,0 +1,376 @@
+//! An opt-in, hardened allocation subsystem for sensitive buffers such as key material.
+//!
+//! Each allocation is flanked by an inaccessible guard page on either side, so that reads or
+//! writes that run off the end of the buffer fault instead of silently corrupting adjacent
+//! memory. A canary value is stashed directly after the user region and checked against a copy
+//! kept in the allocation header on `secure_free`, catching smaller overruns that stay within the
+//! mapped pages and would otherwise not reach a guard page. Freshly allocated memory is filled
+//! with a recognizable garbage pattern to help surface reads of uninitialized data, the user
+//! pages are locked into RAM so the secret never gets swapped to disk, and the region is zeroed
+//! before being released.
+
+use std::mem;
+
+const CANARY_SIZE: usize = 16;
+const GARBAGE_BYTE: u8 = 0xd0;
+
+/// The allocation header, stored in the guarded mapping just before the user region.
+///
+/// `base`/`total_len` describe the whole reservation (guard slack included) so it can be
+/// released in one shot; `protect_start`/`protect_len` describe the sub-range that was actually
+/// made readable/writable and locked, so it can be unlocked without redoing the alignment math;
+/// `size` and `canary` are needed again on `secure_free` to find the trailing canary and to zero
+/// the right number of bytes.
+#[repr(C)]
+struct Header {
+    base: *mut u8,
+    total_len: usize,
+    protect_start: *mut u8,
+    protect_len: usize,
+    size: usize,
+    canary: [u8; CANARY_SIZE],
+}
+
+/// Allocates a `size`-Byte block aligned to `align` Bytes, guarded on both sides by inaccessible
+/// pages.
+///
+/// Returns a null pointer on allocation failure.
+///
+/// # Parameters
+///
+/// * `size`: The size of the allocation in bytes.
+/// * `align`: The alignment of the allocation (at least the size of `usize` on the current
+///   platform). Must also be a power of two.
+pub fn secure_alloc(size: usize, align: usize) -> *mut () {
+    assert!(align.is_power_of_two(), "align must be a power of two");
+    assert!(align >= mem::size_of::<usize>(),
+        "align must be at least {}", mem::size_of::<usize>());
+
+    imp::secure_alloc(size, align)
+}
+
+/// Releases memory allocated with `secure_alloc`.
+///
+/// The user region is zeroed before the mapping is unlocked and released. If the canary
+/// adjacent to the user region was overwritten, this aborts the process instead of returning,
+/// since that means a buffer overflow already happened and the heap can no longer be trusted.
+///
+/// Unsafe because calling this with a pointer that was not returned by `secure_alloc` (or that
+/// has already been freed) causes undefined behavior.
+pub unsafe fn secure_free(ptr: *mut ()) {
+    imp::secure_free(ptr)
+}
+
+/// Returns the process-wide canary value, generating it from the OS RNG on first use.
+fn canary() -> &'static [u8; CANARY_SIZE] {
+    use std::sync::OnceLock;
+
+    static CANARY: OnceLock<[u8; CANARY_SIZE]> = OnceLock::new();
+
+    CANARY.get_or_init(|| {
+        let mut canary = [0; CANARY_SIZE];
+        imp::fill_random(&mut canary);
+        canary
+    })
+}
+
+/// Rounds `value` up to the next multiple of `align` (`align` must be a power of two).
+fn round_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Rounds `value` down to the previous multiple of `align` (`align` must be a power of two).
+fn round_down(value: usize, align: usize) -> usize {
+    value & !(align - 1)
+}
+
+/// How many bytes to reserve so that, no matter where the OS happens to map it, a guarded and
+/// properly-aligned user region can always be carved out of it.
+///
+/// Reserving is cheap (it's just address space, not committed memory), so this is deliberately
+/// generous: a full guard page up front, up to `align - 1` bytes of slack to land the header on
+/// an `align`-aligned user pointer, the header/back-pointer/user/canary region itself, up to
+/// `page_size - 1` bytes of slack to round the protected range up to a page boundary, and a full
+/// guard page at the end.
+fn reserve_len(size: usize, align: usize, page_size: usize) -> usize {
+    let core_len = mem::size_of::<Header>() + mem::size_of::<usize>() + size + CANARY_SIZE;
+    page_size + (align - 1) + core_len + (page_size - 1) + page_size
+}
+
+/// Given the (page-aligned) address the OS mapped `reserve_len(size, align, page_size)` Bytes at,
+/// works out where the header, the user pointer, and the sub-range that needs to be made
+/// readable/writable and locked should go.
+///
+/// Returns `(header_addr, user_addr, protect_start, protect_len)`.
+fn locate(base: usize, size: usize, align: usize, page_size: usize)
+    -> (usize, usize, usize, usize) {
+    let header_len = mem::size_of::<Header>();
+    let back_ptr_len = mem::size_of::<usize>();
+
+    // Leave a full guard page before the header, then round the user pointer up to `align`.
+    // Since that only ever *increases* the address, any slack this consumes only widens the
+    // inaccessible gap before the header; the leading guard page always stays a full page.
+    let user_addr = round_up(base + page_size + header_len + back_ptr_len, align);
+    let header_addr = user_addr - header_len - back_ptr_len;
+    let content_end = user_addr + size + CANARY_SIZE;
+
+    let protect_start = round_down(header_addr, page_size);
+    let protect_end = round_up(content_end, page_size);
+
+    (header_addr, user_addr, protect_start, protect_end - protect_start)
+}
+
+#[cfg(unix)]
+mod imp {
+    extern crate libc;
+
+    use self::libc::{c_void, size_t, off_t,
+        mmap, munmap, mprotect, mlock, munlock,
+        PROT_NONE, PROT_READ, PROT_WRITE, MAP_PRIVATE, MAP_ANON, MAP_FAILED};
+
+    use std::{fs, io, mem, ptr};
+    use std::io::Read;
+
+    use super::{Header, CANARY_SIZE, GARBAGE_BYTE, canary, reserve_len, locate};
+
+    fn page_size() -> usize {
+        unsafe { self::libc::sysconf(self::libc::_SC_PAGESIZE) as usize }
+    }
+
+    pub fn secure_alloc(size: usize, align: usize) -> *mut () {
+        let page_size = page_size();
+        let total_len = reserve_len(size, align, page_size);
+
+        unsafe {
+            let base = mmap(ptr::null_mut(), total_len as size_t, PROT_NONE,
+                MAP_PRIVATE | MAP_ANON, -1, 0 as off_t);
+            if base == MAP_FAILED {
+                return ptr::null_mut();
+            }
+            let base = base as usize;
+
+            let (header_addr, user_addr, protect_start, protect_len) =
+                locate(base, size, align, page_size);
+
+            if mprotect(protect_start as *mut c_void, protect_len as size_t,
+                PROT_READ | PROT_WRITE) != 0 {
+                munmap(base as *mut c_void, total_len as size_t);
+                return ptr::null_mut();
+            }
+            // Best-effort: being unable to lock the pages doesn't make the allocation unusable,
+            // just less hardened against swapping.
+            mlock(protect_start as *const c_void, protect_len as size_t);
+
+            let user_ptr = user_addr as *mut u8;
+            let canary_ptr = user_ptr.add(size);
+            let back_ptr = user_ptr.sub(mem::size_of::<usize>());
+
+            ptr::write(header_addr as *mut Header, Header {
+                base: base as *mut u8,
+                total_len,
+                protect_start: protect_start as *mut u8,
+                protect_len,
+                size,
+                canary: *canary(),
+            });
+            ptr::write(back_ptr as *mut usize, header_addr);
+            ptr::write_bytes(user_ptr, GARBAGE_BYTE, size);
+            ptr::copy_nonoverlapping(canary().as_ptr(), canary_ptr, CANARY_SIZE);
+
+            user_ptr as *mut ()
+        }
+    }
+
+    pub unsafe fn secure_free(ptr: *mut ()) {
+        let user_ptr = ptr as *mut u8;
+        let back_ptr = user_ptr.sub(mem::size_of::<usize>()) as *const usize;
+        let header_ptr = *back_ptr as *mut Header;
+        let header = ptr::read(header_ptr);
+
+        let canary_ptr = user_ptr.add(header.size);
+        let mut actual_canary = [0u8; CANARY_SIZE];
+        ptr::copy_nonoverlapping(canary_ptr, actual_canary.as_mut_ptr(), CANARY_SIZE);
+        if actual_canary != header.canary {
+            // The canary only catches this *after* the overflow already happened, but letting
+            // the program carry on with a smashed heap is worse than aborting it here.
+            self::libc::abort();
+        }
+
+        ptr::write_bytes(user_ptr, 0, header.size);
+
+        munlock(header.protect_start as *const c_void, header.protect_len as size_t);
+        munmap(header.base as *mut c_void, header.total_len as size_t);
+    }
+
+    pub fn fill_random(buf: &mut [u8]) {
+        // Best-effort: fall back to leaving the buffer zeroed if `/dev/urandom` can't be read,
+        // rather than panicking in what is meant to be a hardened allocator's init path.
+        if let Ok(mut f) = fs::File::open("/dev/urandom") {
+            let _: io::Result<usize> = f.read(buf);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    extern crate winapi;
+    extern crate kernel32;
+
+    use self::kernel32::{VirtualAlloc, VirtualFree, VirtualLock, VirtualUnlock, GetSystemInfo};
+    use self::winapi::{MEM_COMMIT, MEM_RESERVE, MEM_RELEASE, PAGE_NOACCESS, PAGE_READWRITE,
+        SIZE_T, LPVOID, DWORD, SYSTEM_INFO, BOOLEAN, ULONG};
+
+    use std::{mem, ptr};
+
+    use super::{Header, CANARY_SIZE, GARBAGE_BYTE, canary, reserve_len, locate};
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        #[link_name = "SystemFunction036"]
+        fn RtlGenRandom(buf: LPVOID, len: ULONG) -> BOOLEAN;
+    }
+
+    fn page_size() -> usize {
+        let mut info: SYSTEM_INFO = unsafe { mem::uninitialized() };
+        unsafe { GetSystemInfo(&mut info); }
+        info.dwPageSize as usize
+    }
+
+    pub fn secure_alloc(size: usize, align: usize) -> *mut () {
+        let page_size = page_size();
+        let total_len = reserve_len(size, align, page_size);
+
+        unsafe {
+            // Step 1: reserve the whole guarded range without committing any of it.
+            let base = VirtualAlloc(ptr::null_mut(), total_len as SIZE_T, MEM_RESERVE,
+                PAGE_NOACCESS);
+            if base.is_null() {
+                return ptr::null_mut();
+            }
+            let base = base as usize;
+
+            let (header_addr, user_addr, protect_start, protect_len) =
+                locate(base, size, align, page_size);
+
+            // Step 2: commit just the sub-range that needs to be readable/writable; committing a
+            // sub-range of an existing reservation at a fixed address is valid without releasing
+            // it first, so the guard pages on either side stay reserved-but-inaccessible.
+            let committed = VirtualAlloc(protect_start as LPVOID, protect_len as SIZE_T,
+                MEM_COMMIT, PAGE_READWRITE);
+            if committed.is_null() {
+                VirtualFree(base as LPVOID, 0, MEM_RELEASE);
+                return ptr::null_mut();
+            }
+
+            VirtualLock(protect_start as LPVOID, protect_len as SIZE_T);
+
+            let user_ptr = user_addr as *mut u8;
+            let canary_ptr = user_ptr.add(size);
+            let back_ptr = user_ptr.sub(mem::size_of::<usize>());
+
+            ptr::write(header_addr as *mut Header, Header {
+                base: base as *mut u8,
+                total_len,
+                protect_start: protect_start as *mut u8,
+                protect_len,
+                size,
+                canary: *canary(),
+            });
+            ptr::write(back_ptr as *mut usize, header_addr);
+            ptr::write_bytes(user_ptr, GARBAGE_BYTE, size);
+            ptr::copy_nonoverlapping(canary().as_ptr(), canary_ptr, CANARY_SIZE);
+
+            user_ptr as *mut ()
+        }
+    }
+
+    pub unsafe fn secure_free(ptr: *mut ()) {
+        let user_ptr = ptr as *mut u8;
+        let back_ptr = user_ptr.sub(mem::size_of::<usize>()) as *const usize;
+        let header_ptr = *back_ptr as *mut Header;
+        let header = ptr::read(header_ptr);
+
+        let canary_ptr = user_ptr.add(header.size);
+        let mut actual_canary = [0u8; CANARY_SIZE];
+        ptr::copy_nonoverlapping(canary_ptr, actual_canary.as_mut_ptr(), CANARY_SIZE);
+        if actual_canary != header.canary {
+            // The canary only catches this *after* the overflow already happened, but letting
+            // the program carry on with a smashed heap is worse than aborting it here.
+            ::std::process::abort();
+        }
+
+        ptr::write_bytes(user_ptr, 0, header.size);
+
+        VirtualUnlock(header.protect_start as LPVOID, header.protect_len as SIZE_T);
+        VirtualFree(header.base as LPVOID, 0, MEM_RELEASE);
+    }
+
+    pub fn fill_random(buf: &mut [u8]) {
+        unsafe {
+            RtlGenRandom(buf.as_mut_ptr() as LPVOID, buf.len() as ULONG);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{mem, ptr};
+
+    #[test]
+    fn round_trip() {
+        let ptr = secure_alloc(64, 8);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % 8, 0);
+        unsafe { secure_free(ptr) };
+    }
+
+    #[test]
+    fn round_trip_over_aligned() {
+        // Exercises the fix where the final address has to be rounded up to `align` directly,
+        // rather than rounding an offset from a base that isn't itself `align`-aligned: a
+        // mapping's base address is only guaranteed page-aligned, and `align` here intentionally
+        // exceeds the page size on most platforms.
+        let align = 1024 * 1024;
+        let ptr = secure_alloc(128, align);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { secure_free(ptr) };
+    }
+
+    #[test]
+    fn garbage_fill_on_alloc() {
+        let ptr = secure_alloc(32, 8) as *mut u8;
+        assert!(!ptr.is_null());
+        unsafe {
+            for i in 0..32 {
+                assert_eq!(*ptr.add(i), GARBAGE_BYTE);
+            }
+            secure_free(ptr as *mut ());
+        }
+    }
+
+    #[test]
+    fn canary_corruption_is_detected() {
+        // `secure_free` itself aborts the process on a mismatch, which can't be caught from
+        // within a normal `#[test]`; this checks the detection logic it relies on instead of
+        // driving the process-abort path itself, by smashing the byte right after the user
+        // region and re-reading the canary the same way `secure_free` does.
+        let ptr = secure_alloc(16, 8) as *mut u8;
+        assert!(!ptr.is_null());
+        unsafe {
+            *ptr.add(16) ^= 0xff;
+
+            let back_ptr = ptr.sub(mem::size_of::<usize>()) as *const usize;
+            let header_ptr = *back_ptr as *const Header;
+            let canary_ptr = ptr.add((*header_ptr).size);
+            let mut actual_canary = [0u8; CANARY_SIZE];
+            ptr::copy_nonoverlapping(canary_ptr, actual_canary.as_mut_ptr(), CANARY_SIZE);
+            assert_ne!(actual_canary, (*header_ptr).canary);
+
+            // Repair the canary so `secure_free` doesn't abort the test process.
+            *ptr.add(16) ^= 0xff;
+            secure_free(ptr as *mut ());
+        }
+    }
+}